@@ -1,9 +1,15 @@
-use std::os::raw::c_ushort;
+use std::{
+    collections::{HashMap, HashSet},
+    os::raw::c_ushort,
+};
 
 use cocoa::{
     appkit::{NSEvent, NSEventModifierFlags},
     base::id,
 };
+use core_foundation::{base::CFRelease, data::CFDataGetBytePtr};
+use lazy_static::lazy_static;
+use std::sync::Mutex;
 
 use crate::{
     dpi::LogicalSize,
@@ -14,6 +20,16 @@ use crate::{
     },
 };
 
+// This file assumes the following companion changes land alongside it in
+// `crate::event` (tracked there, not here, same as `DEVICE_ID`/`IdRef` above
+// already being defined outside this module):
+//   - `KeyboardInput` gains `key_code: KeyCode`, `key_location: KeyLocation`,
+//     `text_with_all_modifiers: Option<&'static str>` and
+//     `text_with_no_modifiers: Option<&'static str>`.
+//   - `WindowEvent` gains a `ReceivedImeText(String)` variant.
+//   - `VirtualKeyCode` gains `Function`, `Eisuu`, `Ro`, `NumpadClear`, `Kana`
+//     and `Capital` variants for the JIS/modifier scancodes below.
+
 #[derive(Debug)]
 pub enum EventWrapper {
     StaticEvent(Event<'static, Never>),
@@ -29,168 +45,630 @@ pub enum EventProxy {
     },
 }
 
-pub fn char_to_keycode(c: char) -> Option<VirtualKeyCode> {
-    // We only translate keys that are affected by keyboard layout.
-    //
-    // Note that since keys are translated in a somewhat "dumb" way (reading character)
-    // there is a concern that some combination, i.e. Cmd+char, causes the wrong
-    // letter to be received, and so we receive the wrong key.
-    //
-    // Implementation reference: https://github.com/WebKit/webkit/blob/82bae82cf0f329dbe21059ef0986c4e92fea4ba6/Source/WebCore/platform/cocoa/KeyEventCocoa.mm#L626
-    Some(match c {
-        'a' | 'A' => VirtualKeyCode::A,
-        'b' | 'B' => VirtualKeyCode::B,
-        'c' | 'C' => VirtualKeyCode::C,
-        'd' | 'D' => VirtualKeyCode::D,
-        'e' | 'E' => VirtualKeyCode::E,
-        'f' | 'F' => VirtualKeyCode::F,
-        'g' | 'G' => VirtualKeyCode::G,
-        'h' | 'H' => VirtualKeyCode::H,
-        'i' | 'I' => VirtualKeyCode::I,
-        'j' | 'J' => VirtualKeyCode::J,
-        'k' | 'K' => VirtualKeyCode::K,
-        'l' | 'L' => VirtualKeyCode::L,
-        'm' | 'M' => VirtualKeyCode::M,
-        'n' | 'N' => VirtualKeyCode::N,
-        'o' | 'O' => VirtualKeyCode::O,
-        'p' | 'P' => VirtualKeyCode::P,
-        'q' | 'Q' => VirtualKeyCode::Q,
-        'r' | 'R' => VirtualKeyCode::R,
-        's' | 'S' => VirtualKeyCode::S,
-        't' | 'T' => VirtualKeyCode::T,
-        'u' | 'U' => VirtualKeyCode::U,
-        'v' | 'V' => VirtualKeyCode::V,
-        'w' | 'W' => VirtualKeyCode::W,
-        'x' | 'X' => VirtualKeyCode::X,
-        'y' | 'Y' => VirtualKeyCode::Y,
-        'z' | 'Z' => VirtualKeyCode::Z,
-        '1' | '!' => VirtualKeyCode::Key1,
-        '2' | '@' => VirtualKeyCode::Key2,
-        '3' | '#' => VirtualKeyCode::Key3,
-        '4' | '$' => VirtualKeyCode::Key4,
-        '5' | '%' => VirtualKeyCode::Key5,
-        '6' | '^' => VirtualKeyCode::Key6,
-        '7' | '&' => VirtualKeyCode::Key7,
-        '8' | '*' => VirtualKeyCode::Key8,
-        '9' | '(' => VirtualKeyCode::Key9,
-        '0' | ')' => VirtualKeyCode::Key0,
-        '=' | '+' => VirtualKeyCode::Equals,
-        '-' | '_' => VirtualKeyCode::Minus,
-        ']' | '}' => VirtualKeyCode::RBracket,
-        '[' | '{' => VirtualKeyCode::LBracket,
-        '\'' | '"' => VirtualKeyCode::Apostrophe,
-        ';' | ':' => VirtualKeyCode::Semicolon,
-        '\\' | '|' => VirtualKeyCode::Backslash,
-        ',' | '<' => VirtualKeyCode::Comma,
-        '/' | '?' => VirtualKeyCode::Slash,
-        '.' | '>' => VirtualKeyCode::Period,
-        '`' | '~' => VirtualKeyCode::Grave,
+/// Which copy of a duplicated key was pressed (Shift, Control, Alt, Logo, or
+/// the numpad), mirroring the W3C UI Events `KeyboardEvent.location` values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyLocation {
+    Standard,
+    Left,
+    Right,
+    Numpad,
+}
+
+/// A key identified by its physical position on the keyboard rather than the
+/// character it produces, named after the W3C UI Events `code` values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyCode {
+    KeyA,
+    KeyB,
+    KeyC,
+    KeyD,
+    KeyE,
+    KeyF,
+    KeyG,
+    KeyH,
+    KeyI,
+    KeyJ,
+    KeyK,
+    KeyL,
+    KeyM,
+    KeyN,
+    KeyO,
+    KeyP,
+    KeyQ,
+    KeyR,
+    KeyS,
+    KeyT,
+    KeyU,
+    KeyV,
+    KeyW,
+    KeyX,
+    KeyY,
+    KeyZ,
+    Digit0,
+    Digit1,
+    Digit2,
+    Digit3,
+    Digit4,
+    Digit5,
+    Digit6,
+    Digit7,
+    Digit8,
+    Digit9,
+    Backquote,
+    Minus,
+    Equal,
+    BracketLeft,
+    BracketRight,
+    Backslash,
+    Semicolon,
+    Quote,
+    Comma,
+    Period,
+    Slash,
+    Space,
+    Tab,
+    Enter,
+    Backspace,
+    Escape,
+    ShiftLeft,
+    ShiftRight,
+    ControlLeft,
+    ControlRight,
+    AltLeft,
+    AltRight,
+    MetaLeft,
+    MetaRight,
+    Numpad0,
+    Numpad1,
+    Numpad2,
+    Numpad3,
+    Numpad4,
+    Numpad5,
+    Numpad6,
+    Numpad7,
+    Numpad8,
+    Numpad9,
+    NumpadAdd,
+    NumpadSubtract,
+    NumpadMultiply,
+    NumpadDivide,
+    NumpadDecimal,
+    NumpadEqual,
+    NumpadEnter,
+    NumpadClear,
+    /// Any key not (yet) given its own physical-position variant; the
+    /// layout-dependent `VirtualKeyCode` reported alongside it is still
+    /// accurate, only the physical-position reporting falls back.
+    Unidentified,
+}
+
+/// Maps a macOS virtual keycode (i.e. `get_scancode`'s `NSEvent::keyCode`) to
+/// its physical position, independent of the active keyboard layout.
+pub fn scancode_to_physical_keycode(scancode: c_ushort) -> (KeyCode, KeyLocation) {
+    match scancode {
+        0x00 => (KeyCode::KeyA, KeyLocation::Standard),
+        0x01 => (KeyCode::KeyS, KeyLocation::Standard),
+        0x02 => (KeyCode::KeyD, KeyLocation::Standard),
+        0x03 => (KeyCode::KeyF, KeyLocation::Standard),
+        0x04 => (KeyCode::KeyH, KeyLocation::Standard),
+        0x05 => (KeyCode::KeyG, KeyLocation::Standard),
+        0x06 => (KeyCode::KeyZ, KeyLocation::Standard),
+        0x07 => (KeyCode::KeyX, KeyLocation::Standard),
+        0x08 => (KeyCode::KeyC, KeyLocation::Standard),
+        0x09 => (KeyCode::KeyV, KeyLocation::Standard),
+        0x0b => (KeyCode::KeyB, KeyLocation::Standard),
+        0x0c => (KeyCode::KeyQ, KeyLocation::Standard),
+        0x0d => (KeyCode::KeyW, KeyLocation::Standard),
+        0x0e => (KeyCode::KeyE, KeyLocation::Standard),
+        0x0f => (KeyCode::KeyR, KeyLocation::Standard),
+        0x10 => (KeyCode::KeyY, KeyLocation::Standard),
+        0x11 => (KeyCode::KeyT, KeyLocation::Standard),
+        0x12 => (KeyCode::Digit1, KeyLocation::Standard),
+        0x13 => (KeyCode::Digit2, KeyLocation::Standard),
+        0x14 => (KeyCode::Digit3, KeyLocation::Standard),
+        0x15 => (KeyCode::Digit4, KeyLocation::Standard),
+        0x16 => (KeyCode::Digit6, KeyLocation::Standard),
+        0x17 => (KeyCode::Digit5, KeyLocation::Standard),
+        0x18 => (KeyCode::Equal, KeyLocation::Standard),
+        0x19 => (KeyCode::Digit9, KeyLocation::Standard),
+        0x1a => (KeyCode::Digit7, KeyLocation::Standard),
+        0x1b => (KeyCode::Minus, KeyLocation::Standard),
+        0x1c => (KeyCode::Digit8, KeyLocation::Standard),
+        0x1d => (KeyCode::Digit0, KeyLocation::Standard),
+        0x1e => (KeyCode::BracketRight, KeyLocation::Standard),
+        0x1f => (KeyCode::KeyO, KeyLocation::Standard),
+        0x20 => (KeyCode::KeyU, KeyLocation::Standard),
+        0x21 => (KeyCode::BracketLeft, KeyLocation::Standard),
+        0x22 => (KeyCode::KeyI, KeyLocation::Standard),
+        0x23 => (KeyCode::KeyP, KeyLocation::Standard),
+        0x24 => (KeyCode::Enter, KeyLocation::Standard),
+        0x25 => (KeyCode::KeyL, KeyLocation::Standard),
+        0x26 => (KeyCode::KeyJ, KeyLocation::Standard),
+        0x27 => (KeyCode::Quote, KeyLocation::Standard),
+        0x28 => (KeyCode::KeyK, KeyLocation::Standard),
+        0x29 => (KeyCode::Semicolon, KeyLocation::Standard),
+        0x2a => (KeyCode::Backslash, KeyLocation::Standard),
+        0x2b => (KeyCode::Comma, KeyLocation::Standard),
+        0x2c => (KeyCode::Slash, KeyLocation::Standard),
+        0x2d => (KeyCode::KeyN, KeyLocation::Standard),
+        0x2e => (KeyCode::KeyM, KeyLocation::Standard),
+        0x2f => (KeyCode::Period, KeyLocation::Standard),
+        0x30 => (KeyCode::Tab, KeyLocation::Standard),
+        0x31 => (KeyCode::Space, KeyLocation::Standard),
+        0x32 => (KeyCode::Backquote, KeyLocation::Standard),
+        0x33 => (KeyCode::Backspace, KeyLocation::Standard),
+        0x35 => (KeyCode::Escape, KeyLocation::Standard),
+        0x36 => (KeyCode::MetaRight, KeyLocation::Right),
+        0x37 => (KeyCode::MetaLeft, KeyLocation::Left),
+        0x38 => (KeyCode::ShiftLeft, KeyLocation::Left),
+        0x3a => (KeyCode::AltLeft, KeyLocation::Left),
+        0x3b => (KeyCode::ControlLeft, KeyLocation::Left),
+        0x3c => (KeyCode::ShiftRight, KeyLocation::Right),
+        0x3d => (KeyCode::AltRight, KeyLocation::Right),
+        0x3e => (KeyCode::ControlRight, KeyLocation::Right),
+        0x41 => (KeyCode::NumpadDecimal, KeyLocation::Numpad),
+        0x43 => (KeyCode::NumpadMultiply, KeyLocation::Numpad),
+        0x45 => (KeyCode::NumpadAdd, KeyLocation::Numpad),
+        0x48 => (KeyCode::NumpadClear, KeyLocation::Numpad),
+        0x4b => (KeyCode::NumpadDivide, KeyLocation::Numpad),
+        0x4c => (KeyCode::NumpadEnter, KeyLocation::Numpad),
+        0x4e => (KeyCode::NumpadSubtract, KeyLocation::Numpad),
+        0x51 => (KeyCode::NumpadEqual, KeyLocation::Numpad),
+        0x52 => (KeyCode::Numpad0, KeyLocation::Numpad),
+        0x53 => (KeyCode::Numpad1, KeyLocation::Numpad),
+        0x54 => (KeyCode::Numpad2, KeyLocation::Numpad),
+        0x55 => (KeyCode::Numpad3, KeyLocation::Numpad),
+        0x56 => (KeyCode::Numpad4, KeyLocation::Numpad),
+        0x57 => (KeyCode::Numpad5, KeyLocation::Numpad),
+        0x58 => (KeyCode::Numpad6, KeyLocation::Numpad),
+        0x59 => (KeyCode::Numpad7, KeyLocation::Numpad),
+        0x5b => (KeyCode::Numpad8, KeyLocation::Numpad),
+        0x5c => (KeyCode::Numpad9, KeyLocation::Numpad),
+        _ => (KeyCode::Unidentified, KeyLocation::Standard),
+    }
+}
+
+// Bindings for the bits of Carbon/TIS we need to query the active keyboard
+// layout. These aren't exposed by the `cocoa` crate.
+#[allow(non_upper_case_globals, non_snake_case)]
+mod tis {
+    use core_foundation::{base::CFTypeRef, string::CFStringRef};
+    use std::os::raw::{c_uchar, c_ushort};
+
+    pub const kUCKeyActionDown: u16 = 0;
+    pub const kUCKeyTranslateNoDeadKeysBit: u32 = 1 << 0;
+
+    extern "C" {
+        pub fn TISCopyCurrentKeyboardLayoutInputSource() -> CFTypeRef;
+        pub fn TISGetInputSourceProperty(
+            input_source: CFTypeRef,
+            property_key: CFStringRef,
+        ) -> CFTypeRef;
+        pub fn LMGetKbdType() -> c_uchar;
+        pub static kTISPropertyUnicodeKeyLayoutData: CFStringRef;
+
+        pub fn UCKeyTranslate(
+            key_layout_ptr: *const u8,
+            virtual_key_code: c_ushort,
+            key_action: u16,
+            modifier_key_state: u32,
+            keyboard_type: u32,
+            key_translate_options: u32,
+            dead_key_state: *mut u32,
+            max_string_length: usize,
+            actual_string_length: *mut usize,
+            unicode_string: *mut u16,
+        ) -> i32;
+    }
+}
+
+// A Unicode control character or a C0/C1 control code, as filtered out by
+// WebKit when interpreting the output of `UCKeyTranslate`:
+// https://github.com/WebKit/webkit/blob/82bae82cf0f329dbe21059ef0986c4e92fea4ba6/Source/WebCore/platform/cocoa/KeyEventCocoa.mm#L626
+fn is_control_character(c: char) -> bool {
+    let c = c as u32;
+    c <= 0x1f || (0x7f..=0x9f).contains(&c)
+}
+
+// A snapshot of the keyboard layout currently selected in System Preferences,
+// built by asking `UCKeyTranslate` what each physical key produces with no
+// modifiers and with Shift held. This replaces the previous fixed US-QWERTY
+// tables, which produced the wrong `VirtualKeyCode` on non-US layouts (and
+// under Cmd, which WebKit/Chromium also special-case by always translating
+// with the Shift-only modifier mask).
+struct KeyboardLayout {
+    char_to_keycode: HashMap<char, VirtualKeyCode>,
+    keycode_to_char: HashMap<(VirtualKeyCode, bool), char>,
+    // The scancode that currently produces each letter/digit/symbol
+    // `VirtualKeyCode`, e.g. under AZERTY this maps the physical A-row key at
+    // scancode 0x0c (labelled 'Q' on a US keyboard) to `VirtualKeyCode::A`,
+    // since that's the key that actually types 'a'. Consulted by
+    // `scancode_to_keycode` before falling back to the fixed US-position
+    // table for non-printable keys.
+    scancode_to_keycode: HashMap<c_ushort, VirtualKeyCode>,
+    // Kept around (rather than just used to build the maps above) so dead-key
+    // composition can keep translating against the same layout the reverse
+    // maps were built from, without re-querying TIS on every keystroke.
+    data: Option<LayoutData>,
+}
+
+impl KeyboardLayout {
+    fn build() -> Self {
+        let mut layout = KeyboardLayout {
+            char_to_keycode: HashMap::new(),
+            keycode_to_char: HashMap::new(),
+            scancode_to_keycode: HashMap::new(),
+            data: None,
+        };
+
+        let layout_data = unsafe { current_keyboard_layout_data() };
+        let layout_data = match layout_data {
+            Some(data) => data,
+            // If we couldn't query the layout (e.g. running in a headless
+            // test environment) fall back to an empty table; lookups in it
+            // get `None` back, and `scancode_to_keycode` then uses its fixed
+            // table.
+            None => return layout,
+        };
+
+        for scancode in 0..0x80u16 {
+            // Only consider scancodes that name an actual physical key;
+            // `UCKeyTranslate` on an unused scancode tends to produce noise
+            // that wouldn't map to any canonical `VirtualKeyCode` anyway.
+            if scancode_to_keycode_static(scancode as c_ushort).is_none() {
+                continue;
+            }
+
+            for &shift in &[false, true] {
+                if let Some(c) =
+                    unsafe { translate(&layout_data, scancode, shift_modifier_mask(shift)) }
+                {
+                    record_layout_sample(&mut layout, scancode as c_ushort, shift, c);
+                }
+            }
+        }
+
+        layout.data = Some(layout_data);
+        layout
+    }
+}
+
+// Canonicalizes one (scancode, shift, produced character) sample into
+// `layout`'s reverse maps. Split out of `KeyboardLayout::build` so the
+// round-trip behavior (notably: which `VirtualKeyCode` a character maps to
+// is decided by the character, not by the scancode that happened to produce
+// it) can be unit tested with synthetic samples instead of a live layout.
+fn record_layout_sample(layout: &mut KeyboardLayout, scancode: c_ushort, shift: bool, c: char) {
+    if is_control_character(c) {
+        return;
+    }
+
+    // The `VirtualKeyCode` a produced character canonically belongs to is
+    // determined by the character itself (`'a'` is always
+    // `VirtualKeyCode::A`), never by which scancode happened to produce it
+    // under the active layout - that's exactly the inversion this table
+    // exists to avoid.
+    let canonical_keycode = match ascii_char_to_keycode(c) {
+        Some(keycode) => keycode,
+        None => return,
+    };
+
+    layout.keycode_to_char.insert((canonical_keycode, shift), c);
+    layout.char_to_keycode.entry(c).or_insert(canonical_keycode);
+    if !shift {
+        layout
+            .scancode_to_keycode
+            .insert(scancode, canonical_keycode);
+    }
+}
+
+// The fixed, layout-independent identity of a `VirtualKeyCode`: `'a'` is
+// `VirtualKeyCode::A` no matter which physical key produced it. Used to
+// canonicalize `KeyboardLayout::build`'s reverse maps; unlike
+// `scancode_to_keycode_static` this is keyed by character, not by scancode.
+fn ascii_char_to_keycode(c: char) -> Option<VirtualKeyCode> {
+    Some(match c.to_ascii_lowercase() {
+        'a' => VirtualKeyCode::A,
+        'b' => VirtualKeyCode::B,
+        'c' => VirtualKeyCode::C,
+        'd' => VirtualKeyCode::D,
+        'e' => VirtualKeyCode::E,
+        'f' => VirtualKeyCode::F,
+        'g' => VirtualKeyCode::G,
+        'h' => VirtualKeyCode::H,
+        'i' => VirtualKeyCode::I,
+        'j' => VirtualKeyCode::J,
+        'k' => VirtualKeyCode::K,
+        'l' => VirtualKeyCode::L,
+        'm' => VirtualKeyCode::M,
+        'n' => VirtualKeyCode::N,
+        'o' => VirtualKeyCode::O,
+        'p' => VirtualKeyCode::P,
+        'q' => VirtualKeyCode::Q,
+        'r' => VirtualKeyCode::R,
+        's' => VirtualKeyCode::S,
+        't' => VirtualKeyCode::T,
+        'u' => VirtualKeyCode::U,
+        'v' => VirtualKeyCode::V,
+        'w' => VirtualKeyCode::W,
+        'x' => VirtualKeyCode::X,
+        'y' => VirtualKeyCode::Y,
+        'z' => VirtualKeyCode::Z,
+        '0' => VirtualKeyCode::Key0,
+        '1' => VirtualKeyCode::Key1,
+        '2' => VirtualKeyCode::Key2,
+        '3' => VirtualKeyCode::Key3,
+        '4' => VirtualKeyCode::Key4,
+        '5' => VirtualKeyCode::Key5,
+        '6' => VirtualKeyCode::Key6,
+        '7' => VirtualKeyCode::Key7,
+        '8' => VirtualKeyCode::Key8,
+        '9' => VirtualKeyCode::Key9,
+        '-' => VirtualKeyCode::Minus,
+        '=' => VirtualKeyCode::Equals,
+        '[' => VirtualKeyCode::LBracket,
+        ']' => VirtualKeyCode::RBracket,
+        '\\' => VirtualKeyCode::Backslash,
+        ';' => VirtualKeyCode::Semicolon,
+        '\'' => VirtualKeyCode::Apostrophe,
+        ',' => VirtualKeyCode::Comma,
+        '.' => VirtualKeyCode::Period,
+        '/' => VirtualKeyCode::Slash,
+        '`' => VirtualKeyCode::Grave,
+        ' ' => VirtualKeyCode::Space,
         _ => return None,
     })
 }
 
-pub fn keycode_to_char(keycode: VirtualKeyCode, modifiers_state: ModifiersState) -> Option<char> {
-    // Reverse translation of keycode to char, based on char_to_keycode
-    Some(match (keycode, modifiers_state.contains(ModifiersState::SHIFT)) {
-        (VirtualKeyCode::A, false) => 'a',
-        (VirtualKeyCode::A, true) => 'A',
-        (VirtualKeyCode::B, false) => 'b',
-        (VirtualKeyCode::B, true) => 'B',
-        (VirtualKeyCode::C, false) => 'c',
-        (VirtualKeyCode::C, true) => 'C',
-        (VirtualKeyCode::D, false) => 'd',
-        (VirtualKeyCode::D, true) => 'D',
-        (VirtualKeyCode::E, false) => 'e',
-        (VirtualKeyCode::E, true) => 'E',
-        (VirtualKeyCode::F, false) => 'f',
-        (VirtualKeyCode::F, true) => 'F',
-        (VirtualKeyCode::G, false) => 'g',
-        (VirtualKeyCode::G, true) => 'G',
-        (VirtualKeyCode::H, false) => 'h',
-        (VirtualKeyCode::H, true) => 'H',
-        (VirtualKeyCode::I, false) => 'i',
-        (VirtualKeyCode::I, true) => 'I',
-        (VirtualKeyCode::J, false) => 'j',
-        (VirtualKeyCode::J, true) => 'J',
-        (VirtualKeyCode::K, false) => 'k',
-        (VirtualKeyCode::K, true) => 'K',
-        (VirtualKeyCode::L, false) => 'l',
-        (VirtualKeyCode::L, true) => 'L',
-        (VirtualKeyCode::M, false) => 'm',
-        (VirtualKeyCode::M, true) => 'M',
-        (VirtualKeyCode::N, false) => 'n',
-        (VirtualKeyCode::N, true) => 'N',
-        (VirtualKeyCode::O, false) => 'o',
-        (VirtualKeyCode::O, true) => 'O',
-        (VirtualKeyCode::P, false) => 'p',
-        (VirtualKeyCode::P, true) => 'P',
-        (VirtualKeyCode::Q, false) => 'q',
-        (VirtualKeyCode::Q, true) => 'Q',
-        (VirtualKeyCode::R, false) => 'r',
-        (VirtualKeyCode::R, true) => 'R',
-        (VirtualKeyCode::S, false) => 's',
-        (VirtualKeyCode::S, true) => 'S',
-        (VirtualKeyCode::T, false) => 't',
-        (VirtualKeyCode::T, true) => 'T',
-        (VirtualKeyCode::U, false) => 'u',
-        (VirtualKeyCode::U, true) => 'U',
-        (VirtualKeyCode::V, false) => 'v',
-        (VirtualKeyCode::V, true) => 'V',
-        (VirtualKeyCode::W, false) => 'w',
-        (VirtualKeyCode::W, true) => 'W',
-        (VirtualKeyCode::X, false) => 'x',
-        (VirtualKeyCode::X, true) => 'X',
-        (VirtualKeyCode::Y, false) => 'y',
-        (VirtualKeyCode::Y, true) => 'Y',
-        (VirtualKeyCode::Z, false) => 'z',
-        (VirtualKeyCode::Z, true) => 'Z',
-        (VirtualKeyCode::Key1, false) => '1',
-        (VirtualKeyCode::Key1, true) => '!',
-        (VirtualKeyCode::Key2, false) => '2',
-        (VirtualKeyCode::Key2, true) => '@',
-        (VirtualKeyCode::Key3, false) => '3',
-        (VirtualKeyCode::Key3, true) => '#',
-        (VirtualKeyCode::Key4, false) => '4',
-        (VirtualKeyCode::Key4, true) => '$',
-        (VirtualKeyCode::Key5, false) => '5',
-        (VirtualKeyCode::Key5, true) => '%',
-        (VirtualKeyCode::Key6, false) => '6',
-        (VirtualKeyCode::Key6, true) => '^',
-        (VirtualKeyCode::Key7, false) => '7',
-        (VirtualKeyCode::Key7, true) => '&',
-        (VirtualKeyCode::Key8, false) => '8',
-        (VirtualKeyCode::Key8, true) => '*',
-        (VirtualKeyCode::Key9, false) => '9',
-        (VirtualKeyCode::Key9, true) => '(',
-        (VirtualKeyCode::Key0, false) => '0',
-        (VirtualKeyCode::Key0, true) => ')',
-        (VirtualKeyCode::Equals, false) => '=',
-        (VirtualKeyCode::Equals, true) => '+',
-        (VirtualKeyCode::Minus, false) => '-',
-        (VirtualKeyCode::Minus, true) => '_',
-        (VirtualKeyCode::RBracket, false) => ']',
-        (VirtualKeyCode::RBracket, true) => '}',
-        (VirtualKeyCode::LBracket, false) => '[',
-        (VirtualKeyCode::LBracket, true) => '{',
-        (VirtualKeyCode::Apostrophe, false) => '\'',
-        (VirtualKeyCode::Apostrophe, true) => '"',
-        (VirtualKeyCode::Semicolon, false) => ';',
-        (VirtualKeyCode::Semicolon, true) => ':',
-        (VirtualKeyCode::Backslash, false) => '\\',
-        (VirtualKeyCode::Backslash, true) => '|',
-        (VirtualKeyCode::Comma, false) => ',',
-        (VirtualKeyCode::Comma, true) => '<',
-        (VirtualKeyCode::Slash, false) => '/',
-        (VirtualKeyCode::Slash, true) => '?',
-        (VirtualKeyCode::Period, false) => '.',
-        (VirtualKeyCode::Period, true) => '>',
-        (VirtualKeyCode::Grave, false) => '`',
-        (VirtualKeyCode::Grave, true) => '~',
-        _ => return None,
+// `UCKeyTranslate`'s `modifierKeyState` takes the classic Carbon modifier
+// flags pre-shifted right by 8 (`carbonModifiers >> 8 & 0xff`, per Apple's
+// own sample code); the Shift bit lands at `1 << 1` in that shifted form.
+fn shift_modifier_mask(shift: bool) -> u32 {
+    if shift {
+        1 << 1
+    } else {
+        0
+    }
+}
+
+// Converts `NSEvent::modifierFlags` into the `carbonModifiers >> 8 & 0xff`
+// form `UCKeyTranslate` expects: Cmd at bit 0, Shift at bit 1, Caps Lock at
+// bit 2, Option at bit 3 and Control at bit 4.
+unsafe fn carbon_modifiers(ns_event: id) -> u32 {
+    let flags = NSEvent::modifierFlags(ns_event);
+    let mut carbon = 0u32;
+    if flags.contains(NSEventModifierFlags::NSCommandKeyMask) {
+        carbon |= 1 << 0;
+    }
+    if flags.contains(NSEventModifierFlags::NSShiftKeyMask) {
+        carbon |= 1 << 1;
+    }
+    if flags.contains(NSEventModifierFlags::NSAlphaShiftKeyMask) {
+        carbon |= 1 << 2;
+    }
+    if flags.contains(NSEventModifierFlags::NSAlternateKeyMask) {
+        carbon |= 1 << 3;
+    }
+    if flags.contains(NSEventModifierFlags::NSControlKeyMask) {
+        carbon |= 1 << 4;
+    }
+    carbon
+}
+
+struct LayoutData {
+    bytes: *const u8,
+    _source: core_foundation::base::CFTypeRef,
+}
+
+// SAFETY: the layout bytes are only ever read from, and the input source
+// that owns them is released when the `LayoutData` is dropped.
+unsafe impl Send for LayoutData {}
+
+impl Drop for LayoutData {
+    fn drop(&mut self) {
+        unsafe { CFRelease(self._source) };
+    }
+}
+
+unsafe fn current_keyboard_layout_data() -> Option<LayoutData> {
+    let input_source = tis::TISCopyCurrentKeyboardLayoutInputSource();
+    if input_source.is_null() {
+        return None;
+    }
+
+    let layout_data =
+        tis::TISGetInputSourceProperty(input_source, tis::kTISPropertyUnicodeKeyLayoutData);
+    if layout_data.is_null() {
+        CFRelease(input_source);
+        return None;
+    }
+
+    let bytes = CFDataGetBytePtr(layout_data as core_foundation::data::CFDataRef);
+    if bytes.is_null() {
+        CFRelease(input_source);
+        return None;
+    }
+
+    Some(LayoutData {
+        bytes,
+        _source: input_source,
     })
 }
 
+unsafe fn translate(layout: &LayoutData, keycode: u16, carbon_modifiers: u32) -> Option<char> {
+    let mut dead_key_state: u32 = 0;
+    translate_raw(
+        layout,
+        keycode,
+        carbon_modifiers,
+        tis::kUCKeyTranslateNoDeadKeysBit,
+        &mut dead_key_state,
+    )
+    .and_then(|s| s.chars().next())
+}
+
+// Unlike `translate`, this doesn't pass `kUCKeyTranslateNoDeadKeysBit`, so a
+// dead key (e.g. ´ on an international layout) can be fed back in via
+// `dead_key_state` on the next call instead of being resolved immediately.
+unsafe fn translate_with_dead_keys(
+    layout: &LayoutData,
+    keycode: u16,
+    carbon_modifiers: u32,
+    dead_key_state: &mut u32,
+) -> Option<String> {
+    translate_raw(layout, keycode, carbon_modifiers, 0, dead_key_state)
+}
+
+unsafe fn translate_raw(
+    layout: &LayoutData,
+    keycode: u16,
+    carbon_modifiers: u32,
+    options: u32,
+    dead_key_state: &mut u32,
+) -> Option<String> {
+    let mut unicode_buf = [0u16; 4];
+    let mut actual_len: usize = 0;
+
+    let status = tis::UCKeyTranslate(
+        layout.bytes,
+        keycode,
+        tis::kUCKeyActionDown,
+        carbon_modifiers,
+        tis::LMGetKbdType() as u32,
+        options,
+        dead_key_state,
+        unicode_buf.len(),
+        &mut actual_len,
+        unicode_buf.as_mut_ptr(),
+    );
+
+    if status != 0 {
+        return None;
+    }
+
+    // An empty string with a non-zero `dead_key_state` means this keystroke
+    // started (or continued) a dead-key sequence rather than producing a
+    // character; the caller should swallow it and retain `dead_key_state`
+    // for the next keystroke.
+    if actual_len == 0 {
+        return None;
+    }
+
+    String::from_utf16(&unicode_buf[..actual_len]).ok()
+}
+
+lazy_static! {
+    static ref CURRENT_LAYOUT: Mutex<Option<KeyboardLayout>> = Mutex::new(None);
+}
+
+fn with_current_layout<T>(f: impl FnOnce(&KeyboardLayout) -> T) -> T {
+    let mut guard = CURRENT_LAYOUT.lock().unwrap();
+    let layout = guard.get_or_insert_with(KeyboardLayout::build);
+    f(layout)
+}
+
+// Called when `NSTextInputContext`/`kTISNotifySelectedKeyboardInputSourceChanged`
+// tells us the user switched keyboard layouts, so the next lookup rebuilds
+// the reverse map from the new layout instead of reusing stale data.
+pub fn invalidate_keyboard_layout_cache() {
+    *CURRENT_LAYOUT.lock().unwrap() = None;
+}
+
+lazy_static! {
+    // Pending dead-key state, e.g. after pressing ´ on an international
+    // layout and before the following key resolves it to é. Keyed by the
+    // owning `NSWindow` pointer rather than kept at the process level, so a
+    // dead key left pending in one window can't leak into text typed in
+    // another (e.g. the user presses ´, then switches windows before typing
+    // the letter it was meant to combine with).
+    static ref DEAD_KEY_STATE: Mutex<HashMap<usize, u32>> = Mutex::new(HashMap::new());
+}
+
+// Identifies the window a keystroke belongs to, for keying `DEAD_KEY_STATE`.
+// Using the `NSWindow` pointer itself (rather than interning some other id)
+// is fine here since we only ever compare it, never dereference it.
+unsafe fn window_key(ns_event: id) -> usize {
+    let window: id = msg_send![ns_event, window];
+    window as usize
+}
+
+// Should be called whenever the window that owns the keyboard focus changes,
+// so a dead key left pending in one window doesn't leak into text typed in
+// another.
+pub unsafe fn reset_dead_key_state(ns_event: id) {
+    DEAD_KEY_STATE.lock().unwrap().remove(&window_key(ns_event));
+}
+
+// Decides whether a `translate_with_dead_keys` result should be reported, or
+// swallowed because it only advanced a dead-key sequence. Split out of
+// `compose_key_text` so the decision can be unit tested without needing a
+// live layout.
+fn resolve_dead_key_translation(produced_text: bool, dead_key_state: u32) -> bool {
+    produced_text || dead_key_state == 0
+}
+
+/// Drops `text` if it's made up entirely of control characters, the same way
+/// `KeyboardLayout::build` already does for its reverse maps: Tab, Return,
+/// Escape and Delete all translate to their ASCII control codes, which would
+/// otherwise get inserted into `ReceivedImeText` as if they were typed text.
+fn strip_control_text(text: Option<String>) -> Option<String> {
+    text.filter(|s| !s.chars().all(is_control_character))
+}
+
+/// Feeds a keystroke through `UCKeyTranslate` with dead-key composition
+/// enabled. Returns the composed text once a full character (or sequence) is
+/// ready, or `None` while a dead key is still pending - in which case no text
+/// is reported for this keystroke. Also returns `None` when the layout
+/// couldn't be queried or the text is made up only of control characters.
+pub unsafe fn compose_key_text(
+    ns_event: id,
+    scancode: c_ushort,
+    carbon_modifiers: u32,
+) -> Option<String> {
+    let mut dead_key_states = DEAD_KEY_STATE.lock().unwrap();
+    let dead_key_state = dead_key_states.entry(window_key(ns_event)).or_insert(0);
+
+    let mut guard = CURRENT_LAYOUT.lock().unwrap();
+    let layout = guard.get_or_insert_with(KeyboardLayout::build);
+    let data = layout.data.as_ref()?;
+
+    let text = translate_with_dead_keys(data, scancode, carbon_modifiers, dead_key_state);
+
+    if !resolve_dead_key_translation(text.is_some(), *dead_key_state) {
+        // A dead key is pending; keep the state for the next keystroke and
+        // report nothing for this one.
+        return None;
+    }
+
+    strip_control_text(text)
+}
+
+pub fn char_to_keycode(c: char) -> Option<VirtualKeyCode> {
+    with_current_layout(|layout| layout.char_to_keycode.get(&c).copied())
+}
+
+pub fn keycode_to_char(keycode: VirtualKeyCode, modifiers_state: ModifiersState) -> Option<char> {
+    let shift = modifiers_state.contains(ModifiersState::SHIFT);
+    with_current_layout(|layout| layout.keycode_to_char.get(&(keycode, shift)).copied())
+}
+
+/// Maps a scancode to the `VirtualKeyCode` it currently produces. Letters,
+/// digits and symbols are resolved against the active keyboard layout (so an
+/// AZERTY user pressing the key that types 'q' gets `VirtualKeyCode::Q`, not
+/// whatever sits at that position on a US keyboard); keys whose meaning
+/// doesn't depend on the layout (Tab, Return, arrows, modifiers, numpad, ...)
+/// fall back to the fixed table below.
 pub fn scancode_to_keycode(scancode: c_ushort) -> Option<VirtualKeyCode> {
+    with_current_layout(|layout| layout.scancode_to_keycode.get(&scancode).copied())
+        .or_else(|| scancode_to_keycode_static(scancode))
+}
+
+// The fixed US-position table `scancode_to_keycode` used to be: correct for
+// non-printable keys (whose identity doesn't change with the layout), but
+// wrong for letters/digits/symbols on any non-US layout. Kept as the
+// fallback for the former, and to seed `KeyboardLayout::build`'s iteration.
+fn scancode_to_keycode_static(scancode: c_ushort) -> Option<VirtualKeyCode> {
     Some(match scancode {
         0x00 => VirtualKeyCode::A,
         0x01 => VirtualKeyCode::S,
@@ -249,13 +727,13 @@ pub fn scancode_to_keycode(scancode: c_ushort) -> Option<VirtualKeyCode> {
         0x36 => VirtualKeyCode::RWin,
         0x37 => VirtualKeyCode::LWin,
         0x38 => VirtualKeyCode::LShift,
-        //0x39 => Caps lock,
+        0x39 => VirtualKeyCode::Capital,
         0x3a => VirtualKeyCode::LAlt,
         0x3b => VirtualKeyCode::LControl,
         0x3c => VirtualKeyCode::RShift,
         0x3d => VirtualKeyCode::RAlt,
         0x3e => VirtualKeyCode::RControl,
-        //0x3f => Fn key,
+        0x3f => VirtualKeyCode::Function,
         0x40 => VirtualKeyCode::F17,
         0x41 => VirtualKeyCode::Decimal,
         //0x42 -> unkown,
@@ -264,7 +742,7 @@ pub fn scancode_to_keycode(scancode: c_ushort) -> Option<VirtualKeyCode> {
         0x45 => VirtualKeyCode::Add,
         //0x46 => unkown,
         0x47 => VirtualKeyCode::Numlock,
-        //0x48 => KeypadClear,
+        0x48 => VirtualKeyCode::NumpadClear,
         0x49 => VirtualKeyCode::VolumeUp,
         0x4a => VirtualKeyCode::VolumeDown,
         0x4b => VirtualKeyCode::Divide,
@@ -286,7 +764,7 @@ pub fn scancode_to_keycode(scancode: c_ushort) -> Option<VirtualKeyCode> {
         0x5b => VirtualKeyCode::Numpad8,
         0x5c => VirtualKeyCode::Numpad9,
         0x5d => VirtualKeyCode::Yen,
-        //0x5e => JIS Ro,
+        0x5e => VirtualKeyCode::Ro,
         //0x5f => unkown,
         0x60 => VirtualKeyCode::F5,
         0x61 => VirtualKeyCode::F6,
@@ -294,9 +772,9 @@ pub fn scancode_to_keycode(scancode: c_ushort) -> Option<VirtualKeyCode> {
         0x63 => VirtualKeyCode::F3,
         0x64 => VirtualKeyCode::F8,
         0x65 => VirtualKeyCode::F9,
-        //0x66 => JIS Eisuu (macOS),
+        0x66 => VirtualKeyCode::Eisuu,
         0x67 => VirtualKeyCode::F11,
-        //0x68 => JIS Kanna (macOS),
+        0x68 => VirtualKeyCode::Kana,
         0x69 => VirtualKeyCode::F13,
         0x6a => VirtualKeyCode::F16,
         0x6b => VirtualKeyCode::F14,
@@ -372,34 +850,285 @@ pub fn get_scancode(event: cocoa::base::id) -> c_ushort {
     unsafe { msg_send![event, keyCode] }
 }
 
+lazy_static! {
+    // Dedupes leaked strings so retyping the same character doesn't leak a
+    // new `Box<str>` every time.
+    static ref STRING_CACHE: Mutex<HashSet<&'static str>> = Mutex::new(HashSet::new());
+}
+
+fn intern_string(s: String) -> &'static str {
+    let mut cache = STRING_CACHE.lock().unwrap();
+    if let Some(&interned) = cache.get(s.as_str()) {
+        return interned;
+    }
+    let interned: &'static str = Box::leak(s.into_boxed_str());
+    cache.insert(interned);
+    interned
+}
+
+fn text_for_modifiers(scancode: c_ushort, carbon_modifiers: u32) -> Option<&'static str> {
+    let mut guard = CURRENT_LAYOUT.lock().unwrap();
+    let layout = guard.get_or_insert_with(KeyboardLayout::build);
+    let data = layout.data.as_ref()?;
+    let c = unsafe { translate(data, scancode, carbon_modifiers) }?;
+    if is_control_character(c) {
+        return None;
+    }
+    Some(intern_string(c.to_string()))
+}
+
+/// The text this key produces with the modifiers currently held, e.g. "?"
+/// for Slash while Cmd+Shift is down on a US layout. Intended for matching
+/// keyboard shortcuts like Cmd+Shift+/ by character rather than by
+/// layout-dependent `VirtualKeyCode`.
+pub unsafe fn text_with_all_modifiers(ns_event: id) -> Option<&'static str> {
+    text_for_modifiers(get_scancode(ns_event), carbon_modifiers(ns_event))
+}
+
+/// The same as [`text_with_all_modifiers`], but with Ctrl and Cmd masked out
+/// first; Shift, Option and Caps Lock still apply. Cmd and Ctrl don't change which character a key *means* to the
+/// user - they just route it to the app instead of inserting it - so a
+/// shortcut handler for Cmd+/ wants to see "/", not whatever control
+/// character Cmd+Slash would otherwise translate to.
+pub unsafe fn text_with_no_modifiers(ns_event: id) -> Option<&'static str> {
+    let mods = carbon_modifiers(ns_event) & !((1 << 0) | (1 << 4));
+    text_for_modifiers(get_scancode(ns_event), mods)
+}
+
+/// Translates a `keyDown` event into a `WindowEvent::ReceivedImeText`, honoring
+/// any dead key left pending by a previous call. Returns `None` both when the
+/// layout couldn't be queried and when this keystroke only advanced a dead-key
+/// sequence without producing text yet - in the latter case the key event
+/// should still be reported as `KeyboardInput`, just without accompanying text.
+pub unsafe fn key_to_ime_text_event(ns_event: id) -> Option<WindowEvent<'static>> {
+    let scancode = get_scancode(ns_event);
+    let text = compose_key_text(ns_event, scancode, carbon_modifiers(ns_event))?;
+    Some(WindowEvent::ReceivedImeText(text))
+}
+
+/// Builds the `WindowEvent::KeyboardInput` for an ordinary (non-modifier)
+/// `keyDown`/`keyUp`, alongside `key_to_ime_text_event` for the composed
+/// text. This is the path that actually drives `text_with_all_modifiers`/
+/// `text_with_no_modifiers` for character keys; `modifier_event_with_device_mask`
+/// reuses it so modifier-only events stay consistent with it.
+pub unsafe fn key_event(ns_event: id, state: ElementState) -> WindowEvent<'static> {
+    let scancode = get_scancode(ns_event);
+    let virtual_keycode = scancode_to_keycode(scancode);
+    let (key_code, key_location) = scancode_to_physical_keycode(scancode);
+    #[allow(deprecated)]
+    WindowEvent::KeyboardInput {
+        device_id: DEVICE_ID,
+        input: KeyboardInput {
+            state,
+            scancode: scancode as _,
+            virtual_keycode,
+            key_code,
+            key_location,
+            text_with_all_modifiers: text_with_all_modifiers(ns_event),
+            text_with_no_modifiers: text_with_no_modifiers(ns_event),
+            modifiers: event_mods(ns_event),
+        },
+        is_synthetic: false,
+    }
+}
+
+// The device-dependent modifier bits `NSEvent::modifierFlags` also sets
+// alongside the portable `NSShiftKeyMask`-style flags (see `IOLLEvent.h`).
+// Unlike the portable flags, these tell left and right apart, so tracking
+// *them* instead of `NSShiftKeyMask` as a whole lets us fire a separate
+// press/release pair when e.g. right Control goes down while left Control
+// is already held.
+#[allow(non_upper_case_globals)]
+pub mod device_modifier_mask {
+    pub const NX_DEVICELCTLKEYMASK: u64 = 0x00000001;
+    pub const NX_DEVICELSHIFTKEYMASK: u64 = 0x00000002;
+    pub const NX_DEVICERSHIFTKEYMASK: u64 = 0x00000004;
+    pub const NX_DEVICELCMDKEYMASK: u64 = 0x00000008;
+    pub const NX_DEVICERCMDKEYMASK: u64 = 0x00000010;
+    pub const NX_DEVICELALTKEYMASK: u64 = 0x00000020;
+    pub const NX_DEVICERALTKEYMASK: u64 = 0x00000040;
+    pub const NX_DEVICERCTLKEYMASK: u64 = 0x00002000;
+}
+
+unsafe fn raw_modifier_flags(ns_event: id) -> u64 {
+    msg_send![ns_event, modifierFlags]
+}
+
+// Split out of `modifier_event_with_device_mask` so the left/right decision
+// can be unit tested without needing a live `NSEvent`.
+fn is_modifier_pressed(coarse_flag_set: bool, device_mask: u64, raw_flags: u64) -> bool {
+    coarse_flag_set && (device_mask == 0 || raw_flags & device_mask != 0)
+}
+
+/// The original side-agnostic `modifier_event`, kept around so existing call
+/// sites that only track one combined state per modifier (e.g. Caps Lock,
+/// which has no left/right distinction) don't have to pass a device mask.
+/// Equivalent to calling [`modifier_event_with_device_mask`] with `0`.
 pub unsafe fn modifier_event(
     ns_event: id,
     keymask: NSEventModifierFlags,
     was_key_pressed: bool,
 ) -> Option<WindowEvent<'static>> {
-    if !was_key_pressed && NSEvent::modifierFlags(ns_event).contains(keymask)
-        || was_key_pressed && !NSEvent::modifierFlags(ns_event).contains(keymask)
-    {
+    modifier_event_with_device_mask(ns_event, keymask, 0, was_key_pressed)
+}
+
+/// Like [`modifier_event`], but additionally gated on a device-dependent mask
+/// from [`device_modifier_mask`] so callers can track the left and right side
+/// of Shift/Control/Option/Command independently instead of only seeing a
+/// single combined state per modifier. Pass `0` for `device_mask` to fall
+/// back to the old side-agnostic behavior.
+pub unsafe fn modifier_event_with_device_mask(
+    ns_event: id,
+    keymask: NSEventModifierFlags,
+    device_mask: u64,
+    was_key_pressed: bool,
+) -> Option<WindowEvent<'static>> {
+    let is_key_pressed = is_modifier_pressed(
+        NSEvent::modifierFlags(ns_event).contains(keymask),
+        device_mask,
+        raw_modifier_flags(ns_event),
+    );
+
+    if is_key_pressed != was_key_pressed {
         let state = if was_key_pressed {
             ElementState::Released
         } else {
             ElementState::Pressed
         };
 
-        let scancode = get_scancode(ns_event);
-        let virtual_keycode = scancode_to_keycode(scancode);
-        #[allow(deprecated)]
-        Some(WindowEvent::KeyboardInput {
-            device_id: DEVICE_ID,
-            input: KeyboardInput {
-                state,
-                scancode: scancode as _,
-                virtual_keycode,
-                modifiers: event_mods(ns_event),
-            },
-            is_synthetic: false,
-        })
+        Some(key_event(ns_event, state))
     } else {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_characters_are_filtered() {
+        assert!(is_control_character('\t'));
+        assert!(is_control_character('\r'));
+        assert!(is_control_character('\x1b'));
+        assert!(is_control_character('\x7f'));
+        assert!(!is_control_character('a'));
+        assert!(!is_control_character('/'));
+    }
+
+    #[test]
+    fn strips_text_made_up_only_of_control_characters() {
+        assert_eq!(strip_control_text(Some("\t".to_owned())), None);
+        assert_eq!(
+            strip_control_text(Some("e".to_owned())),
+            Some("e".to_owned())
+        );
+        assert_eq!(strip_control_text(None), None);
+    }
+
+    #[test]
+    fn physical_keycode_maps_alphanumeric_row() {
+        assert_eq!(
+            scancode_to_physical_keycode(0x00),
+            (KeyCode::KeyA, KeyLocation::Standard)
+        );
+        assert_eq!(
+            scancode_to_physical_keycode(0x12),
+            (KeyCode::Digit1, KeyLocation::Standard)
+        );
+    }
+
+    #[test]
+    fn physical_keycode_distinguishes_left_and_right_modifiers() {
+        assert_eq!(
+            scancode_to_physical_keycode(0x38),
+            (KeyCode::ShiftLeft, KeyLocation::Left)
+        );
+        assert_eq!(
+            scancode_to_physical_keycode(0x3c),
+            (KeyCode::ShiftRight, KeyLocation::Right)
+        );
+    }
+
+    #[test]
+    fn unmapped_scancode_falls_back_to_unidentified() {
+        assert_eq!(
+            scancode_to_physical_keycode(0x34),
+            (KeyCode::Unidentified, KeyLocation::Standard)
+        );
+    }
+
+    fn empty_layout() -> KeyboardLayout {
+        KeyboardLayout {
+            char_to_keycode: HashMap::new(),
+            keycode_to_char: HashMap::new(),
+            scancode_to_keycode: HashMap::new(),
+            data: None,
+        }
+    }
+
+    #[test]
+    fn layout_reverse_map_round_trips_by_character_not_scancode() {
+        // Simulates an AZERTY layout: the physical key at scancode 0x0c (the
+        // "Q" position on a US keyboard) produces 'a', and the key at 0x00
+        // (the "A" position) produces 'q'.
+        let mut layout = empty_layout();
+        record_layout_sample(&mut layout, 0x0c, false, 'a');
+        record_layout_sample(&mut layout, 0x00, false, 'q');
+
+        assert_eq!(layout.char_to_keycode.get(&'a'), Some(&VirtualKeyCode::A));
+        assert_eq!(layout.char_to_keycode.get(&'q'), Some(&VirtualKeyCode::Q));
+        assert_eq!(
+            layout.keycode_to_char.get(&(VirtualKeyCode::A, false)),
+            Some(&'a')
+        );
+        assert_eq!(
+            layout.scancode_to_keycode.get(&0x0c),
+            Some(&VirtualKeyCode::A)
+        );
+        assert_eq!(
+            layout.scancode_to_keycode.get(&0x00),
+            Some(&VirtualKeyCode::Q)
+        );
+    }
+
+    #[test]
+    fn layout_reverse_map_ignores_control_characters() {
+        let mut layout = empty_layout();
+        record_layout_sample(&mut layout, 0x24, false, '\r');
+        assert!(layout.char_to_keycode.is_empty());
+        assert!(layout.scancode_to_keycode.is_empty());
+    }
+
+    #[test]
+    fn dead_key_pending_state_is_swallowed() {
+        assert!(!resolve_dead_key_translation(false, 1));
+    }
+
+    #[test]
+    fn dead_key_resolved_state_is_reported() {
+        assert!(resolve_dead_key_translation(true, 1));
+        assert!(resolve_dead_key_translation(false, 0));
+    }
+
+    #[test]
+    fn modifier_pressed_ignores_device_mask_when_zero() {
+        assert!(is_modifier_pressed(true, 0, 0));
+        assert!(!is_modifier_pressed(false, 0, 0xffff));
+    }
+
+    #[test]
+    fn modifier_pressed_requires_matching_device_mask() {
+        use device_modifier_mask::{NX_DEVICELSHIFTKEYMASK, NX_DEVICERSHIFTKEYMASK};
+        assert!(is_modifier_pressed(
+            true,
+            NX_DEVICELSHIFTKEYMASK,
+            NX_DEVICELSHIFTKEYMASK
+        ));
+        assert!(!is_modifier_pressed(
+            true,
+            NX_DEVICELSHIFTKEYMASK,
+            NX_DEVICERSHIFTKEYMASK
+        ));
+    }
+}